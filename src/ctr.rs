@@ -0,0 +1,168 @@
+//! AES-CTR based reader/writer.
+//!
+//! Unlike the CBC mode used by `AesReader`/`AesWriter`, counter mode turns the
+//! block cipher into a keystream generator: the keystream for block `i` is
+//! `E(nonce || counter_base + i)`, XOR'd with the plaintext. Ciphertext and
+//! plaintext therefore always have the same length (no padding), and seeking
+//! to an arbitrary byte offset only requires recomputing the keystream block
+//! that offset falls into - no need to read backwards to recover a previous
+//! block's ciphertext.
+
+use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
+use std::mem::size_of;
+
+use crypto::symmetriccipher::BlockEncryptor;
+
+/// Builds the counter block for block index `counter`: the nonce occupies
+/// the high bytes, and `counter` is written big-endian into the low 8 bytes.
+fn counter_block(nonce: &[u8], block_size: usize, counter: u64) -> Vec<u8> {
+    let mut block = vec![0u8; block_size];
+    block[..nonce.len()].copy_from_slice(nonce);
+    let counter_bytes = counter.to_be_bytes();
+    let start = block_size - counter_bytes.len();
+    block[start..].copy_from_slice(&counter_bytes);
+    block
+}
+
+/// A nonce longer than `block_size - size_of::<u64>()` would have its
+/// trailing bytes silently overwritten by the counter in `counter_block`, so two
+/// nonces differing only past that point would produce the same keystream -
+/// a catastrophic CTR two-time-pad. Reject that up front instead.
+fn check_nonce_len(nonce: &[u8], block_size: usize) -> Result<()> {
+    let max_len = block_size - size_of::<u64>();
+    if nonce.len() > max_len {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            format!("nonce must be at most {} bytes for a {}-byte block", max_len, block_size)));
+    }
+    Ok(())
+}
+
+/// Generates the AES-CTR keystream one block at a time.
+struct Keystream<E: BlockEncryptor> {
+    enc: E,
+    nonce: Vec<u8>,
+    counter: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<E: BlockEncryptor> Keystream<E> {
+    fn new(enc: E, nonce: Vec<u8>) -> Result<Keystream<E>> {
+        let block_size = enc.block_size();
+        check_nonce_len(&nonce, block_size)?;
+        Ok(Keystream {
+            enc: enc,
+            nonce: nonce,
+            counter: 0,
+            block: vec![0u8; block_size],
+            pos: block_size,
+        })
+    }
+
+    fn block_size(&self) -> usize {
+        self.block.len()
+    }
+
+    /// Jumps to the keystream byte at `offset` from the start of the stream.
+    fn seek(&mut self, offset: u64) {
+        let block_size = self.block_size() as u64;
+        self.counter = offset / block_size;
+        let input = counter_block(&self.nonce, self.block_size(), self.counter);
+        self.enc.encrypt_block(&input, &mut self.block);
+        self.counter += 1;
+        self.pos = (offset % block_size) as usize;
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.pos == self.block_size() {
+            let input = counter_block(&self.nonce, self.block_size(), self.counter);
+            self.enc.encrypt_block(&input, &mut self.block);
+            self.counter += 1;
+            self.pos = 0;
+        }
+        let byte = self.block[self.pos];
+        self.pos += 1;
+        byte
+    }
+
+    fn xor(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            *byte ^= self.next_byte();
+        }
+    }
+}
+
+pub struct AesCtrWriter<E: BlockEncryptor, W: Write> {
+    writer: Option<W>,
+    keystream: Keystream<E>,
+}
+
+impl<E: BlockEncryptor, W: Write> AesCtrWriter<E, W> {
+    pub fn new(writer: W, enc: E, nonce: Vec<u8>) -> Result<AesCtrWriter<E, W>> {
+        Ok(AesCtrWriter {
+            writer: Some(writer),
+            keystream: Keystream::new(enc, nonce)?,
+        })
+    }
+
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<E: BlockEncryptor, W: Write> Write for AesCtrWriter<E, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut enc = buf.to_vec();
+        self.keystream.xor(&mut enc);
+        self.writer.as_mut().unwrap().write_all(&enc)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl<E: BlockEncryptor, W: Write> Drop for AesCtrWriter<E, W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            // drop impls should not panic, therefore ignore the result of flush
+            let _ = self.flush();
+        }
+    }
+}
+
+pub struct AesCtrReader<E: BlockEncryptor, R: Read> {
+    reader: R,
+    keystream: Keystream<E>,
+}
+
+impl<E: BlockEncryptor, R: Read> AesCtrReader<E, R> {
+    pub fn new(reader: R, enc: E, nonce: Vec<u8>) -> Result<AesCtrReader<E, R>> {
+        Ok(AesCtrReader {
+            reader: reader,
+            keystream: Keystream::new(enc, nonce)?,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<E: BlockEncryptor, R: Read> Read for AesCtrReader<E, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.keystream.xor(&mut buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<E: BlockEncryptor, R: Read + Seek> Seek for AesCtrReader<E, R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let offset = self.reader.seek(pos)?;
+        self.keystream.seek(offset);
+        Ok(offset)
+    }
+}
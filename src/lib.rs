@@ -1,26 +1,43 @@
 extern crate crypto;
+extern crate rand;
+
+mod ctr;
+mod aead;
+mod password;
+mod mode;
+
+pub use ctr::{AesCtrReader, AesCtrWriter};
+pub use aead::{AeadReader, AeadWriter, DEFAULT_CHUNK_SIZE, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE};
+pub use mode::{StreamMode, CbcEncryptMode, CbcDecryptMode};
 
 #[cfg(test)] mod tests;
 
-use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
+use std::io::{Read, Write, Seek, SeekFrom, BufRead, Result, Error, ErrorKind};
 
-use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor, Encryptor, Decryptor};
-use crypto::blockmodes::{PkcsPadding, CbcEncryptor, CbcDecryptor, EncPadding, DecPadding};
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
 use crypto::buffer::{RefReadBuffer, RefWriteBuffer, BufferResult, WriteBuffer, ReadBuffer};
 
 const BUFFER_SIZE: usize = 8192;
 
-pub struct AesWriter<E: BlockEncryptor, W: Write> {
+pub struct AesWriter<M: StreamMode, W: Write> {
     writer: Option<W>,
-    enc: CbcEncryptor<E, EncPadding<PkcsPadding>>,
+    mode: M,
     closed: bool,
 }
 
-impl<E: BlockEncryptor, W: Write> AesWriter<E, W> {
-    pub fn new(writer: W, enc: E, iv: Vec<u8>) -> AesWriter<E, W> {
+impl<E: BlockEncryptor, W: Write> AesWriter<CbcEncryptMode<E>, W> {
+    pub fn new(writer: W, enc: E, iv: Vec<u8>) -> AesWriter<CbcEncryptMode<E>, W> {
+        AesWriter::with_mode(writer, CbcEncryptMode::new(enc, iv))
+    }
+}
+
+impl<M: StreamMode, W: Write> AesWriter<M, W> {
+    /// Builds an `AesWriter` around any `StreamMode`, for callers that want
+    /// a mode other than the default CBC + PKCS padding used by `new`.
+    pub fn with_mode(writer: W, mode: M) -> AesWriter<M, W> {
         AesWriter {
             writer: Some(writer),
-            enc: CbcEncryptor::new(enc, PkcsPadding, iv),
+            mode: mode,
             closed: false,
         }
     }
@@ -35,8 +52,7 @@ impl<E: BlockEncryptor, W: Write> AesWriter<E, W> {
         let mut out = [0u8; BUFFER_SIZE];
         let mut write_buf = RefWriteBuffer::new(&mut out);
         loop {
-            let res = self.enc.encrypt(&mut read_buf, &mut write_buf, eof)
-                .map_err(|e| Error::new(ErrorKind::Other, format!("encryption error: {:?}", e)))?;
+            let res = self.mode.encrypt(&mut read_buf, &mut write_buf, eof)?;
             let mut enc = write_buf.take_read_buffer();
             let enc = enc.take_remaining();
             self.writer.as_mut().unwrap().write_all(enc)?;
@@ -47,13 +63,13 @@ impl<E: BlockEncryptor, W: Write> AesWriter<E, W> {
                 BufferResult::BufferOverflow => {},
             }
         }
-        // CbcEncryptor has its own internal buffer and always consumes everything
+        // the mode has its own internal buffer and always consumes everything
         assert_eq!(read_buf.remaining(), 0);
         Ok(buf.len())
     }
 }
 
-impl<E: BlockEncryptor, W: Write> Write for AesWriter<E, W> {
+impl<M: StreamMode, W: Write> Write for AesWriter<M, W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         if self.closed {
             return Err(Error::new(ErrorKind::Other, "AesWriter is closed"));
@@ -72,7 +88,7 @@ impl<E: BlockEncryptor, W: Write> Write for AesWriter<E, W> {
     }
 }
 
-impl<E: BlockEncryptor, W: Write> Drop for AesWriter<E, W> {
+impl<M: StreamMode, W: Write> Drop for AesWriter<M, W> {
     fn drop(&mut self) {
         if self.writer.is_some() {
             // drop impls should not panic, therefore ignore the result of flush
@@ -81,30 +97,45 @@ impl<E: BlockEncryptor, W: Write> Drop for AesWriter<E, W> {
     }
 }
 
-pub struct AesReader<D: BlockDecryptor, R: Read> {
+pub struct AesReader<M: StreamMode, R: Read> {
     /// Reader to read encrypted data from
     reader: R,
-    /// Decryptor to decrypt data with
-    dec: CbcDecryptor<D, DecPadding<PkcsPadding>>,
+    /// Mode to decrypt data with
+    mode: M,
     /// IV used if seeked to the first block
     iv: Vec<u8>,
-    /// Block size of BlockDecryptor, needed when seeking to correctly seek to the nearest block
+    /// Block size of the mode, needed when seeking to correctly seek to the nearest block
     block_size: usize,
     /// Buffer used to store blob needed to find out if we reached eof
     buffer: Vec<u8>,
     /// Indicates wheather eof of the underlying buffer was reached
     eof: bool,
+    /// Already-decrypted plaintext not yet consumed by the caller, used to
+    /// implement `BufRead` without an extra `BufReader` layer on top
+    plain: Vec<u8>,
+    /// Position of the next unconsumed byte in `plain`
+    plain_pos: usize,
 }
 
-impl<D: BlockDecryptor, R: Read> AesReader<D, R> {
-    pub fn new(reader: R, dec: D, iv: Vec<u8>) -> AesReader<D, R> {
+impl<D: BlockDecryptor, R: Read> AesReader<CbcDecryptMode<D>, R> {
+    pub fn new(reader: R, dec: D, iv: Vec<u8>) -> AesReader<CbcDecryptMode<D>, R> {
+        AesReader::with_mode(reader, CbcDecryptMode::new(dec, iv.clone()), iv)
+    }
+}
+
+impl<M: StreamMode, R: Read> AesReader<M, R> {
+    /// Builds an `AesReader` around any `StreamMode`, for callers that want
+    /// a mode other than the default CBC + PKCS padding used by `new`.
+    pub fn with_mode(reader: R, mode: M, iv: Vec<u8>) -> AesReader<M, R> {
         AesReader {
+            block_size: mode.block_size(),
             reader: reader,
-            block_size: dec.block_size(),
-            iv: iv.clone(),
-            dec: CbcDecryptor::new(dec, PkcsPadding, iv),
+            mode: mode,
+            iv: iv,
             buffer: Vec::new(),
             eof: false,
+            plain: Vec::new(),
+            plain_pos: 0,
         }
     }
 
@@ -112,12 +143,22 @@ impl<D: BlockDecryptor, R: Read> AesReader<D, R> {
         self.reader
     }
 
-    fn fill_buf(&mut self) -> Result<Vec<u8>> {
+    fn fill_source_buf(&mut self) -> Result<Vec<u8>> {
         let mut eof_buffer = vec![0u8; BUFFER_SIZE];
-        let read = self.reader.read(&mut eof_buffer)?;
-        self.eof = read == 0;
-        eof_buffer.truncate(read);
-        Ok(eof_buffer)
+        loop {
+            match self.reader.read(&mut eof_buffer) {
+                Ok(read) => {
+                    self.eof = read == 0;
+                    eof_buffer.truncate(read);
+                    return Ok(eof_buffer);
+                },
+                // a signal-interrupted syscall is not a real error and
+                // reading nothing from it hasn't changed our state yet,
+                // so it's always safe to just retry
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
     }
 
     fn read_decrypt(&mut self, buf: &mut [u8]) -> Result<usize> {
@@ -128,9 +169,8 @@ impl<D: BlockDecryptor, R: Read> AesReader<D, R> {
         {
             let mut read_buf = RefReadBuffer::new(&self.buffer);
 
-            // test if CbcDecryptor still has enough decrypted data
-            res = self.dec.decrypt(&mut read_buf, &mut write_buf, self.eof)
-                .map_err(|e| Error::new(ErrorKind::Other, format!("decryption error: {:?}", e)))?;
+            // test if the mode still has enough decrypted data
+            res = self.mode.decrypt(&mut read_buf, &mut write_buf, self.eof)?;
             remaining = read_buf.remaining();
         }
         // keep remaining bytes
@@ -144,17 +184,16 @@ impl<D: BlockDecryptor, R: Read> AesReader<D, R> {
 
         // if this is the first iteration, fill internal buffer
         if self.buffer.is_empty() && !self.eof {
-            self.buffer = self.fill_buf()?;
+            self.buffer = self.fill_source_buf()?;
         }
 
         let mut dec_len = 0;
         while dec_len == 0 && !self.eof {
-            let eof_buffer = self.fill_buf()?;
+            let eof_buffer = self.fill_source_buf()?;
             let remaining;
             {
                 let mut read_buf = RefReadBuffer::new(&self.buffer);
-                self.dec.decrypt(&mut read_buf, &mut write_buf, self.eof)
-                    .map_err(|e| Error::new(ErrorKind::Other, format!("decryption error: {:?}", e)))?;
+                self.mode.decrypt(&mut read_buf, &mut write_buf, self.eof)?;
                 let mut dec = write_buf.take_read_buffer();
                 let dec = dec.take_remaining();
                 dec_len = dec.len();
@@ -170,31 +209,61 @@ impl<D: BlockDecryptor, R: Read> AesReader<D, R> {
     }
 }
 
-impl<D: BlockDecryptor, R: Read> Read for AesReader<D, R> {
+impl<M: StreamMode, R: Read> Read for AesReader<M, R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let read = self.read_decrypt(buf)?;
-        Ok(read)
+        // BufRead::fill_buf may have decrypted bytes past what consume()
+        // released; hand those out first instead of decrypting past them
+        if self.plain_pos < self.plain.len() {
+            let available = &self.plain[self.plain_pos..];
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            self.plain_pos += n;
+            return Ok(n);
+        }
+        self.read_decrypt(buf)
+    }
+}
+
+impl<M: StreamMode, R: Read> BufRead for AesReader<M, R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.plain_pos >= self.plain.len() {
+            let mut plain = vec![0u8; BUFFER_SIZE];
+            let read = self.read_decrypt(&mut plain)?;
+            plain.truncate(read);
+            self.plain = plain;
+            self.plain_pos = 0;
+        }
+        Ok(&self.plain[self.plain_pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.plain_pos = (self.plain_pos + amt).min(self.plain.len());
     }
 }
 
-impl<D: BlockDecryptor, R: Read + Seek> Seek for AesReader<D, R> {
+impl<M: StreamMode, R: Read + Seek> Seek for AesReader<M, R> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
         match pos {
             SeekFrom::Start(offset) => {
                 let block_num = offset / self.block_size as u64;
                 let block_offset = offset % self.block_size as u64;
-                // reset CbcDecryptor
+                // reset the mode
                 if block_num == 0 {
                     self.reader.seek(SeekFrom::Start(0))?;
-                    self.dec.reset(&self.iv);
+                    self.mode.reset(&self.iv);
                 } else {
                     self.reader.seek(SeekFrom::Start((block_num - 1) * self.block_size as u64))?;
                     let mut iv = vec![0u8; self.block_size];
                     self.reader.read_exact(&mut iv)?;
-                    self.dec.reset(&iv);
+                    self.mode.reset(&iv);
                 }
                 self.buffer = Vec::new();
                 self.eof = false;
+                // discard any plaintext buffered for BufRead from before the
+                // seek - it belongs to the old position and fill_buf must
+                // not hand it back as if it belonged to the new one
+                self.plain = Vec::new();
+                self.plain_pos = 0;
                 let mut skip = vec![0u8; block_offset as usize];
                 self.read_exact(&mut skip)?;
                 Ok(offset)
@@ -0,0 +1,128 @@
+//! Password-based constructors for `AesWriter`/`AesReader`.
+//!
+//! Instead of requiring callers to manage a raw key and IV themselves,
+//! `AesWriter::from_password`/`AesReader::from_password` derive a 256 bit AES
+//! key from a passphrase with scrypt and write a small self-describing
+//! header - magic bytes, scrypt parameters, salt and IV - ahead of the
+//! ciphertext. `AesReader::from_password` reads that header back, re-derives
+//! the key and seeds the decryptor, so the caller never has to touch key
+//! material directly.
+
+use std::io::{Read, Write, Result, Error, ErrorKind};
+
+use rand::{OsRng, Rng};
+
+use crypto::aessafe::{AesSafe256Encryptor, AesSafe256Decryptor};
+use crypto::scrypt::{scrypt, ScryptParams};
+
+use super::{AesWriter, AesReader};
+use super::mode::{CbcEncryptMode, CbcDecryptMode};
+
+/// Identifies an `AesWriter::from_password` header so `from_password` can
+/// reject unrelated input early instead of deriving a key for garbage.
+const MAGIC: &'static [u8; 4] = b"AES1";
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+
+/// scrypt cost parameter `log2(N)`.
+const LOG_N: u8 = 15;
+/// scrypt block size parameter.
+const R: u32 = 8;
+/// scrypt parallelization parameter.
+const P: u32 = 1;
+
+/// Upper bound on the `log_n`/`r`/`p` header fields accepted by
+/// `AesReader::from_password`. Those fields are read straight out of
+/// untrusted ciphertext, and `ScryptParams::new` asserts `log_n` is nonzero
+/// and below `16 * r` - so a corrupted or hostile header must be rejected with an
+/// `io::Error` here, before it ever reaches scrypt, rather than panicking.
+const MAX_LOG_N: u8 = 24;
+const MAX_R: u32 = 1024;
+const MAX_P: u32 = 1024;
+
+/// `crypto::scrypt::scrypt` allocates a `128 * r * N` byte buffer plus a
+/// `128 * r * p` byte buffer, where `N = 2^log_n`. `log_n`, `r` and `p`
+/// bounded independently still lets a hostile header pick all three near
+/// their caps and demand ~2 TiB (`log_n = 24, r = 1024`) - bound the
+/// actual memory the derivation would allocate instead.
+const MAX_SCRYPT_MEM: u64 = 64 * 1024 * 1024;
+
+fn check_scrypt_params(log_n: u8, r: u32, p: u32) -> Result<()> {
+    if log_n == 0 || log_n > MAX_LOG_N || r == 0 || r > MAX_R || p == 0 || p > MAX_P
+        || log_n as u32 >= r * 16 {
+        return Err(Error::new(ErrorKind::InvalidData, "scrypt parameters out of range"));
+    }
+    let n = 1u64 << log_n as u64;
+    let r = r as u64;
+    let p = p as u64;
+    let mem = 128u64.saturating_mul(r).saturating_mul(n.saturating_add(p));
+    if mem > MAX_SCRYPT_MEM {
+        return Err(Error::new(ErrorKind::InvalidData, "scrypt parameters would require too much memory"));
+    }
+    Ok(())
+}
+
+fn derive_key(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32) -> Vec<u8> {
+    let params = ScryptParams::new(log_n, r, p);
+    let mut key = vec![0u8; KEY_LEN];
+    scrypt(password, salt, &params, &mut key);
+    key
+}
+
+impl<W: Write> AesWriter<CbcEncryptMode<AesSafe256Encryptor>, W> {
+    /// Derives a key from `password` with a fresh random salt and IV, writes
+    /// the header describing how to reproduce that derivation, and returns
+    /// an `AesWriter` ready to encrypt with the derived key.
+    pub fn from_password(mut writer: W, password: &[u8]) -> Result<AesWriter<CbcEncryptMode<AesSafe256Encryptor>, W>> {
+        let mut rng = OsRng::new()?;
+        let mut salt = vec![0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        let mut iv = vec![0u8; IV_LEN];
+        rng.fill_bytes(&mut iv);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[LOG_N])?;
+        writer.write_all(&R.to_be_bytes())?;
+        writer.write_all(&P.to_be_bytes())?;
+        writer.write_all(&salt)?;
+        writer.write_all(&iv)?;
+
+        let key = derive_key(password, &salt, LOG_N, R, P);
+        let enc = AesSafe256Encryptor::new(&key);
+        Ok(AesWriter::new(writer, enc, iv))
+    }
+}
+
+impl<R: Read> AesReader<CbcDecryptMode<AesSafe256Decryptor>, R> {
+    /// Reads the header written by `AesWriter::from_password`, re-derives
+    /// the key from `password` and returns an `AesReader` seeded to decrypt
+    /// what follows.
+    pub fn from_password(mut reader: R, password: &[u8]) -> Result<AesReader<CbcDecryptMode<AesSafe256Decryptor>, R>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not an AesWriter::from_password stream"));
+        }
+
+        let mut log_n = [0u8; 1];
+        reader.read_exact(&mut log_n)?;
+        let mut r_bytes = [0u8; 4];
+        reader.read_exact(&mut r_bytes)?;
+        let mut p_bytes = [0u8; 4];
+        reader.read_exact(&mut p_bytes)?;
+        let r = u32::from_be_bytes(r_bytes);
+        let p = u32::from_be_bytes(p_bytes);
+        check_scrypt_params(log_n[0], r, p)?;
+
+        let mut salt = vec![0u8; SALT_LEN];
+        reader.read_exact(&mut salt)?;
+        let mut iv = vec![0u8; IV_LEN];
+        reader.read_exact(&mut iv)?;
+
+        let key = derive_key(password, &salt, log_n[0], r, p);
+        let dec = AesSafe256Decryptor::new(&key);
+        Ok(AesReader::new(reader, dec, iv))
+    }
+}
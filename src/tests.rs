@@ -0,0 +1,314 @@
+use std::io::{Read, Write, Seek, SeekFrom, BufRead, Result, Error, ErrorKind, Cursor};
+
+use rand::{OsRng, Rng};
+
+use crypto::aessafe::{AesSafe256Encryptor, AesSafe256Decryptor};
+
+use crypto::aes::KeySize;
+
+use super::{AesWriter, AesReader, AesCtrReader, AesCtrWriter, AeadReader, AeadWriter};
+
+/// Wraps a reader and scripts the exact sequence of `read` results it hands
+/// back, regardless of what the inner reader would actually return - used to
+/// exercise `AesReader`'s handling of `Interrupted` errors and short reads.
+struct FlakyReader<R: Read> {
+    inner: R,
+    /// One entry per call to `read`: `None` means "pass through to the
+    /// wrapped reader", `Some(n)` means "read at most `n` bytes this call".
+    script: Vec<Option<usize>>,
+}
+
+impl<R: Read> FlakyReader<R> {
+    fn new(inner: R, script: Vec<Option<usize>>) -> FlakyReader<R> {
+        FlakyReader { inner: inner, script: script }
+    }
+}
+
+impl<R: Read> Read for FlakyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.script.is_empty() {
+            return self.inner.read(buf);
+        }
+        match self.script.remove(0) {
+            None => self.inner.read(buf),
+            Some(n) if n == usize::max_value() => {
+                Err(Error::new(ErrorKind::Interrupted, "interrupted"))
+            },
+            Some(n) => {
+                let limit = n.min(buf.len());
+                self.inner.read(&mut buf[..limit])
+            },
+        }
+    }
+}
+
+fn encrypt(plain: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut rng = OsRng::new().unwrap();
+    let mut key = vec![0u8; 32];
+    rng.fill_bytes(&mut key);
+    let mut iv = vec![0u8; 16];
+    rng.fill_bytes(&mut iv);
+
+    let mut cipher = Vec::new();
+    {
+        let enc = AesSafe256Encryptor::new(&key);
+        let mut writer = AesWriter::new(&mut cipher, enc, iv.clone());
+        writer.write_all(plain).unwrap();
+        writer.flush().unwrap();
+    }
+    (cipher, key, iv)
+}
+
+#[test]
+fn read_decrypt_survives_interrupted() {
+    let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+    let (cipher, key, iv) = encrypt(&plain);
+
+    // interrupt the very first read and the third one, letting the
+    // retries in between and everything after pass through
+    let script = vec![Some(usize::max_value()), None, Some(usize::max_value()), None];
+    let flaky = FlakyReader::new(Cursor::new(cipher), script);
+    let dec = AesSafe256Decryptor::new(&key);
+    let mut reader = AesReader::new(flaky, dec, iv);
+
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain);
+}
+
+#[test]
+fn read_decrypt_survives_short_reads() {
+    let plain: Vec<u8> = b"the quick brown fox jumps over the lazy dog".iter().cloned().cycle().take(440).collect();
+    let (cipher, key, iv) = encrypt(&plain);
+
+    // force every underlying read to return just one byte at a time
+    let script: Vec<Option<usize>> = (0..cipher.len() + 1).map(|_| Some(1)).collect();
+    let flaky = FlakyReader::new(Cursor::new(cipher), script);
+    let dec = AesSafe256Decryptor::new(&key);
+    let mut reader = AesReader::new(flaky, dec, iv);
+
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain);
+}
+
+#[test]
+fn ctr_round_trip() {
+    let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+    let mut rng = OsRng::new().unwrap();
+    let mut key = vec![0u8; 32];
+    rng.fill_bytes(&mut key);
+    let mut nonce = vec![0u8; 8];
+    rng.fill_bytes(&mut nonce);
+
+    let mut cipher = Vec::new();
+    {
+        let enc = AesSafe256Encryptor::new(&key);
+        let mut writer = AesCtrWriter::new(&mut cipher, enc, nonce.clone()).unwrap();
+        writer.write_all(&plain).unwrap();
+        writer.flush().unwrap();
+    }
+    assert_eq!(cipher.len(), plain.len());
+
+    // CTR only ever runs the block cipher forward to build the keystream,
+    // so both ends take a `BlockEncryptor`, never a `BlockDecryptor`
+    let enc = AesSafe256Encryptor::new(&key);
+    let mut reader = AesCtrReader::new(Cursor::new(cipher), enc, nonce).unwrap();
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain);
+}
+
+#[test]
+fn ctr_seek_matches_full_decrypt() {
+    let plain: Vec<u8> = (0u8..=255).cycle().take(200).collect();
+    let mut rng = OsRng::new().unwrap();
+    let mut key = vec![0u8; 32];
+    rng.fill_bytes(&mut key);
+    let mut nonce = vec![0u8; 8];
+    rng.fill_bytes(&mut nonce);
+
+    let mut cipher = Vec::new();
+    {
+        let enc = AesSafe256Encryptor::new(&key);
+        let mut writer = AesCtrWriter::new(&mut cipher, enc, nonce.clone()).unwrap();
+        writer.write_all(&plain).unwrap();
+        writer.flush().unwrap();
+    }
+
+    // seek into the middle of the stream, straddling a block boundary, and
+    // confirm the keystream picks up exactly where a full decrypt would be
+    let offset = 37;
+    let enc = AesSafe256Encryptor::new(&key);
+    let mut reader = AesCtrReader::new(Cursor::new(cipher), enc, nonce).unwrap();
+    reader.seek(SeekFrom::Start(offset)).unwrap();
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain[offset as usize..]);
+}
+
+#[test]
+fn ctr_rejects_nonce_too_long_for_block() {
+    let key = vec![0u8; 32];
+    let enc = AesSafe256Encryptor::new(&key);
+    // the 8-byte counter leaves only 8 bytes of a 16-byte block for the
+    // nonce, so a 9-byte nonce must be rejected rather than silently truncated
+    let nonce = vec![0u8; 9];
+    let err = AesCtrWriter::new(Vec::new(), enc, nonce).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+fn aead_encrypt(plain: &[u8]) -> (Vec<u8>, Vec<u8>, [u8; 12]) {
+    let mut rng = OsRng::new().unwrap();
+    let mut key = vec![0u8; 32];
+    rng.fill_bytes(&mut key);
+    let mut nonce = [0u8; 12];
+    rng.fill_bytes(&mut nonce);
+
+    let mut cipher = Vec::new();
+    {
+        let mut writer = AeadWriter::new(&mut cipher, KeySize::KeySize256, key.clone(), nonce);
+        writer.write_all(plain).unwrap();
+        writer.flush().unwrap();
+    }
+    (cipher, key, nonce)
+}
+
+#[test]
+fn aead_round_trip() {
+    let plain: Vec<u8> = b"the quick brown fox jumps over the lazy dog".iter().cloned().cycle().take(1000).collect();
+    let (cipher, key, nonce) = aead_encrypt(&plain);
+
+    let mut reader = AeadReader::new(Cursor::new(cipher), KeySize::KeySize256, key, nonce);
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain);
+}
+
+#[test]
+fn aead_detects_tampered_ciphertext() {
+    let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+    let (mut cipher, key, nonce) = aead_encrypt(&plain);
+
+    // flip a bit in the first chunk's ciphertext; the GCM tag must no
+    // longer match and decryption must fail instead of returning garbage
+    cipher[0] ^= 0x01;
+
+    let mut reader = AeadReader::new(Cursor::new(cipher), KeySize::KeySize256, key, nonce);
+    let mut out = Vec::new();
+    let err = reader.read_to_end(&mut out).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn password_round_trip() {
+    let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+    let password = b"hunter2";
+
+    let mut cipher = Vec::new();
+    {
+        let mut writer = AesWriter::from_password(&mut cipher, password).unwrap();
+        writer.write_all(&plain).unwrap();
+        writer.flush().unwrap();
+    }
+
+    let mut reader = AesReader::from_password(Cursor::new(cipher), password).unwrap();
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain);
+}
+
+#[test]
+fn from_password_rejects_hostile_scrypt_header() {
+    // a from_password header with log_n = 0 would panic inside
+    // ScryptParams::new's own assert if check_scrypt_params let it through
+    let mut header = Vec::new();
+    header.extend_from_slice(b"AES1");
+    header.push(0);
+    header.extend_from_slice(&8u32.to_be_bytes());
+    header.extend_from_slice(&1u32.to_be_bytes());
+    header.extend_from_slice(&[0u8; 16]);
+    header.extend_from_slice(&[0u8; 16]);
+
+    let err = AesReader::from_password(Cursor::new(header), b"hunter2").err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn from_password_rejects_scrypt_params_that_would_exhaust_memory() {
+    // log_n and r are each within their individual bound, but together
+    // would make scrypt allocate gigabytes for its V/B buffers
+    let mut header = Vec::new();
+    header.extend_from_slice(b"AES1");
+    header.push(24);
+    header.extend_from_slice(&1024u32.to_be_bytes());
+    header.extend_from_slice(&1u32.to_be_bytes());
+    header.extend_from_slice(&[0u8; 16]);
+    header.extend_from_slice(&[0u8; 16]);
+
+    let err = AesReader::from_password(Cursor::new(header), b"hunter2").err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn buf_read_reads_lines() {
+    let plain = b"the quick brown fox\njumps over the lazy dog\n".to_vec();
+    let (cipher, key, iv) = encrypt(&plain);
+
+    let dec = AesSafe256Decryptor::new(&key);
+    let reader = AesReader::new(Cursor::new(cipher), dec, iv);
+
+    let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec!["the quick brown fox", "jumps over the lazy dog"]);
+}
+
+#[test]
+fn read_after_partial_consume_returns_remaining_buffered_bytes() {
+    let plain: Vec<u8> = b"the quick brown fox jumps over the lazy dog".iter().cloned().cycle().take(440).collect();
+    let (cipher, key, iv) = encrypt(&plain);
+
+    let dec = AesSafe256Decryptor::new(&key);
+    let mut reader = AesReader::new(Cursor::new(cipher), dec, iv);
+
+    // fill_buf buffers decrypted plaintext into `plain`; consuming only half
+    // of it must not lose the other half to a later plain `Read::read` call
+    let n = reader.fill_buf().unwrap().len();
+    reader.consume(n / 2);
+
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain[n / 2..]);
+}
+
+#[test]
+fn seek_discards_plaintext_buffered_before_the_seek() {
+    let plain: Vec<u8> = (0u8..=255).cycle().take(200).collect();
+    let (cipher, key, iv) = encrypt(&plain);
+
+    let dec = AesSafe256Decryptor::new(&key);
+    let mut reader = AesReader::new(Cursor::new(cipher), dec, iv);
+
+    // buffer plaintext via BufRead without consuming it, then seek away;
+    // the stale pre-seek plaintext must not reappear after the seek
+    reader.fill_buf().unwrap();
+    reader.seek(SeekFrom::Start(100)).unwrap();
+
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, plain[100..]);
+}
+
+#[test]
+fn aead_detects_truncated_stream() {
+    let plain = b"the quick brown fox jumps over the lazy dog".to_vec();
+    let (mut cipher, key, nonce) = aead_encrypt(&plain);
+
+    // drop the final chunk's authentication tag; a truncated stream must
+    // be reported rather than silently treated as complete
+    cipher.truncate(cipher.len() - 16);
+
+    let mut reader = AeadReader::new(Cursor::new(cipher), KeySize::KeySize256, key, nonce);
+    let mut out = Vec::new();
+    let err = reader.read_to_end(&mut out).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}